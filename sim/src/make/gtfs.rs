@@ -0,0 +1,407 @@
+//! Builds transit demand from a published GTFS feed. GTFS identifies everything with opaque
+//! strings, so we intern them into compact integers (see [`GtfsIds`]) while still round-tripping
+//! the original string for debugging and serialization, then map each GTFS stop/route onto the
+//! map's own `BusStopID`/`BusRouteID` and emit `TripSpec::UsingTransit` plans timed to the real
+//! schedule.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use geom::{Duration, LonLat, Time};
+use map_model::{BusRouteID, BusStopID, Map};
+
+use crate::{SidewalkSpot, TripSpec};
+
+/// Which GTFS table a string id came from. GTFS namespaces ids per file, so the same raw string
+/// can legitimately name an unrelated stop, route, and trip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum GtfsKind {
+    Stop,
+    Route,
+    Trip,
+}
+
+/// A compact integer standing in for one GTFS string id. The owning [`GtfsIds`] also remembers the
+/// id's [`GtfsKind`], so ids from different tables never collide even when the raw strings match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct GtfsId(usize);
+
+/// An interning table mapping each (kind, GTFS string) to a [`GtfsId`]. Lookups in both directions
+/// are cheap, and serializing the table preserves the original strings so imported scenarios stay
+/// debuggable.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GtfsIds {
+    entries: Vec<(GtfsKind, String)>,
+    lookup: BTreeMap<(GtfsKind, String), GtfsId>,
+}
+
+impl GtfsIds {
+    pub fn new() -> GtfsIds {
+        GtfsIds::default()
+    }
+
+    /// Returns the id for this (kind, string), interning it if it hasn't been seen before.
+    pub fn get_or_insert(&mut self, kind: GtfsKind, raw: &str) -> GtfsId {
+        let key = (kind, raw.to_string());
+        if let Some(id) = self.lookup.get(&key) {
+            return *id;
+        }
+        let id = GtfsId(self.entries.len());
+        self.entries.push(key.clone());
+        self.lookup.insert(key, id);
+        id
+    }
+
+    /// The original GTFS string for an interned id.
+    pub fn original(&self, id: GtfsId) -> &str {
+        &self.entries[id.0].1
+    }
+}
+
+/// A single stop from `stops.txt`.
+#[derive(Debug, Clone)]
+pub struct Stop {
+    pub stop_id: GtfsId,
+    pub pos: LonLat,
+}
+
+/// A single route from `routes.txt`. `name` is matched against the map's bus route names.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub route_id: GtfsId,
+    pub name: String,
+}
+
+/// A scheduled vehicle run from `trips.txt`, joined with its ordered `stop_times.txt` rows.
+#[derive(Debug, Clone)]
+pub struct Trip {
+    pub route_id: GtfsId,
+    /// Stops in `stop_sequence` order, each paired with its scheduled departure.
+    pub stop_times: Vec<(GtfsId, Time)>,
+}
+
+/// A GTFS feed read from a directory of `.txt` tables. The string ids are interned as the tables
+/// are read, so everything downstream operates on compact [`GtfsId`]s.
+#[derive(Debug, Clone)]
+pub struct GtfsFeed {
+    pub ids: GtfsIds,
+    pub stops: Vec<Stop>,
+    pub routes: Vec<Route>,
+    pub trips: Vec<Trip>,
+}
+
+impl GtfsFeed {
+    /// Reads `stops.txt`, `routes.txt`, `trips.txt`, and `stop_times.txt` from `dir`.
+    pub fn read(dir: &str) -> Result<GtfsFeed> {
+        let mut ids = GtfsIds::new();
+
+        let mut stops = Vec::new();
+        for row in read_table(&format!("{}/stops.txt", dir))? {
+            stops.push(Stop {
+                stop_id: ids.get_or_insert(GtfsKind::Stop, row.get("stop_id")?),
+                pos: LonLat::new(
+                    row.get("stop_lon")?.parse()?,
+                    row.get("stop_lat")?.parse()?,
+                ),
+            });
+        }
+
+        let mut routes = Vec::new();
+        for row in read_table(&format!("{}/routes.txt", dir))? {
+            // GTFS feeds usually have a short name; fall back to the long one.
+            let name = row
+                .get("route_short_name")
+                .or_else(|_| row.get("route_long_name"))?
+                .to_string();
+            routes.push(Route {
+                route_id: ids.get_or_insert(GtfsKind::Route, row.get("route_id")?),
+                name,
+            });
+        }
+
+        // trip_id -> route_id, so stop_times can be grouped back onto their route.
+        let mut trip_routes: BTreeMap<GtfsId, GtfsId> = BTreeMap::new();
+        for row in read_table(&format!("{}/trips.txt", dir))? {
+            let route_id = ids.get_or_insert(GtfsKind::Route, row.get("route_id")?);
+            let trip_id = ids.get_or_insert(GtfsKind::Trip, row.get("trip_id")?);
+            trip_routes.insert(trip_id, route_id);
+        }
+
+        // Gather each trip's stops, remembering stop_sequence so they can be ordered.
+        let mut trip_stops: BTreeMap<GtfsId, Vec<(usize, GtfsId, Time)>> = BTreeMap::new();
+        for row in read_table(&format!("{}/stop_times.txt", dir))? {
+            let trip_id = ids.get_or_insert(GtfsKind::Trip, row.get("trip_id")?);
+            let stop_id = ids.get_or_insert(GtfsKind::Stop, row.get("stop_id")?);
+            let seq: usize = row.get("stop_sequence")?.parse()?;
+            let departure = parse_gtfs_time(row.get("departure_time")?)?;
+            trip_stops
+                .entry(trip_id)
+                .or_insert_with(Vec::new)
+                .push((seq, stop_id, departure));
+        }
+
+        let mut trips = Vec::new();
+        for (trip_id, mut stop_times) in trip_stops {
+            let route_id = match trip_routes.get(&trip_id) {
+                Some(id) => *id,
+                None => continue,
+            };
+            stop_times.sort_by_key(|(seq, _, _)| *seq);
+            trips.push(Trip {
+                route_id,
+                stop_times: stop_times
+                    .into_iter()
+                    .map(|(_, stop, departure)| (stop, departure))
+                    .collect(),
+            });
+        }
+
+        Ok(GtfsFeed {
+            ids,
+            stops,
+            routes,
+            trips,
+        })
+    }
+
+    /// Turns each rider's demand into a `UsingTransit` spec. For every rider we pick the first
+    /// scheduled trip on their route departing no earlier than `earliest_departure`, board at the
+    /// served stop nearest their origin, and alight at the served stop nearest their destination
+    /// that comes later in the route. `start_time` is the scheduled departure from the boarding
+    /// stop. Riders whose route has no map equivalent, or who can't board before they alight, are
+    /// skipped.
+    pub fn make_transit_trips(&self, riders: &[Rider], map: &Map) -> Vec<(Time, TripSpec)> {
+        // GTFS route id -> map route, skipping routes the map doesn't have.
+        let mut route_equiv: BTreeMap<GtfsId, BusRouteID> = BTreeMap::new();
+        for route in &self.routes {
+            if let Some(br) = map.get_bus_route(&route.name) {
+                route_equiv.insert(route.route_id, br.id);
+            } else {
+                info!(
+                    "Skipping GTFS route {}; no map equivalent for {}",
+                    self.ids.original(route.route_id),
+                    route.name
+                );
+            }
+        }
+
+        // GTFS stop id -> GPS position, for picking the nearest boarding/alighting stops.
+        let stop_pos: BTreeMap<GtfsId, LonLat> =
+            self.stops.iter().map(|s| (s.stop_id, s.pos)).collect();
+        let project = |pos: LonLat| pos.to_pt(map.get_gps_bounds());
+
+        let mut results = Vec::new();
+        for rider in riders {
+            let route = match route_equiv.get(&rider.route_id) {
+                Some(route) => *route,
+                None => continue,
+            };
+
+            // The earliest scheduled trip on this route the rider can still catch.
+            let mut trip: Option<&Trip> = None;
+            for candidate in &self.trips {
+                if candidate.route_id != rider.route_id {
+                    continue;
+                }
+                let departs = match candidate.stop_times.first() {
+                    Some((_, departs)) => *departs,
+                    None => continue,
+                };
+                if departs < rider.earliest_departure {
+                    continue;
+                }
+                if trip
+                    .map(|best| departs < best.stop_times[0].1)
+                    .unwrap_or(true)
+                {
+                    trip = Some(candidate);
+                }
+            }
+            let trip = match trip {
+                Some(trip) => trip,
+                None => continue,
+            };
+
+            // The trip's stops that we know positions for, in sequence order.
+            let seq: Vec<(GtfsId, Time, LonLat)> = trip
+                .stop_times
+                .iter()
+                .filter_map(|(id, departs)| stop_pos.get(id).map(|pos| (*id, *departs, *pos)))
+                .collect();
+
+            // Board nearest the origin; alight nearest the destination, but only at a later stop.
+            let board = seq.iter().enumerate().min_by(|(_, a), (_, b)| {
+                project(a.2)
+                    .dist_to(project(rider.origin))
+                    .partial_cmp(&project(b.2).dist_to(project(rider.origin)))
+                    .unwrap()
+            });
+            let board = match board {
+                Some((idx, _)) => idx,
+                None => continue,
+            };
+            let alight = seq.iter().enumerate().skip(board + 1).min_by(|(_, a), (_, b)| {
+                project(a.2)
+                    .dist_to(project(rider.destination))
+                    .partial_cmp(&project(b.2).dist_to(project(rider.destination)))
+                    .unwrap()
+            });
+            let alight = match alight {
+                Some((idx, _)) => idx,
+                None => continue,
+            };
+
+            let (stop1, start_time) = match nearest_bus_stop(seq[board].2, map) {
+                Some(bs) => (bs, seq[board].1),
+                None => continue,
+            };
+            let stop2 = match nearest_bus_stop(seq[alight].2, map) {
+                Some(bs) => bs,
+                None => continue,
+            };
+            results.push((
+                start_time,
+                TripSpec::UsingTransit {
+                    start: SidewalkSpot::bus_stop(stop1, map),
+                    goal: SidewalkSpot::bus_stop(stop2, map),
+                    route,
+                    stop1,
+                    maybe_stop2: Some(stop2),
+                },
+            ));
+        }
+        results
+    }
+}
+
+/// One rider's demand: a GPS origin/destination and the GTFS route they intend to take, no earlier
+/// than `earliest_departure`.
+#[derive(Debug, Clone)]
+pub struct Rider {
+    pub origin: LonLat,
+    pub destination: LonLat,
+    pub route_id: GtfsId,
+    pub earliest_departure: Time,
+}
+
+/// One parsed row of a GTFS table, keyed by column name.
+struct Row(BTreeMap<String, String>);
+
+impl Row {
+    fn get(&self, col: &str) -> Result<&str> {
+        self.0
+            .get(col)
+            .map(|s| s.as_str())
+            .ok_or_else(|| anyhow!("missing column {}", col))
+    }
+}
+
+/// Reads a GTFS `.txt` table into rows keyed by the header columns.
+fn read_table(path: &str) -> Result<Vec<Row>> {
+    let contents = std::fs::read_to_string(path)?;
+    let contents = contents.trim_start_matches('\u{feff}');
+    let mut lines = contents.lines();
+    let header = match lines.next() {
+        Some(line) => parse_csv_line(line),
+        None => return Ok(Vec::new()),
+    };
+    let mut rows = Vec::new();
+    for line in lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut map = BTreeMap::new();
+        for (col, value) in header.iter().zip(parse_csv_line(line)) {
+            map.insert(col.clone(), value);
+        }
+        rows.push(Row(map));
+    }
+    Ok(rows)
+}
+
+/// Splits a single CSV line, honoring double-quoted fields (with `""` escaping).
+fn parse_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut cur = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                cur.push('"');
+                chars.next();
+            }
+            '"' => {
+                in_quotes = !in_quotes;
+            }
+            ',' if !in_quotes => fields.push(std::mem::take(&mut cur)),
+            _ => cur.push(c),
+        }
+    }
+    fields.push(cur);
+    fields.iter().map(|f| f.trim().to_string()).collect()
+}
+
+/// Parses a GTFS `HH:MM:SS` time, which may exceed 24:00:00 for trips running past midnight.
+fn parse_gtfs_time(raw: &str) -> Result<Time> {
+    let parts: Vec<&str> = raw.split(':').collect();
+    if parts.len() != 3 {
+        return Err(anyhow!("not a HH:MM:SS GTFS time: {}", raw));
+    }
+    let hours: f64 = parts[0].parse()?;
+    let minutes: f64 = parts[1].parse()?;
+    let seconds: f64 = parts[2].parse()?;
+    Ok(Time::START_OF_DAY + Duration::seconds(hours * 3600.0 + minutes * 60.0 + seconds))
+}
+
+/// The map bus stop physically closest to a GPS point, or `None` if the map has no bus stops.
+fn nearest_bus_stop(pos: LonLat, map: &Map) -> Option<BusStopID> {
+    let pt = pos.to_pt(map.get_gps_bounds());
+    map.all_bus_stops()
+        .iter()
+        .min_by(|(_, a), (_, b)| {
+            a.sidewalk_pos
+                .pt(map)
+                .dist_to(pt)
+                .partial_cmp(&b.sidewalk_pos.pt(map).dist_to(pt))
+                .unwrap()
+        })
+        .map(|(id, _)| *id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interning_namespaces_per_table() {
+        let mut ids = GtfsIds::new();
+        let stop = ids.get_or_insert(GtfsKind::Stop, "X");
+        let route = ids.get_or_insert(GtfsKind::Route, "X");
+        // Same raw string in different tables must not collide.
+        assert_ne!(stop, route);
+        // But the same (kind, string) interns to the same id, and round-trips the string.
+        assert_eq!(ids.get_or_insert(GtfsKind::Stop, "X"), stop);
+        assert_eq!(ids.original(stop), "X");
+        assert_eq!(ids.original(route), "X");
+    }
+
+    #[test]
+    fn csv_handles_quoting() {
+        assert_eq!(
+            parse_csv_line("a,\"b,c\",\"d\"\"e\""),
+            vec!["a".to_string(), "b,c".to_string(), "d\"e".to_string()]
+        );
+    }
+
+    #[test]
+    fn gtfs_time_past_midnight() {
+        // GTFS allows hours past 24 for trips running into the next service day.
+        assert_eq!(
+            parse_gtfs_time("25:30:00").unwrap(),
+            Time::START_OF_DAY + Duration::seconds(25.0 * 3600.0 + 30.0 * 60.0)
+        );
+    }
+}
@@ -0,0 +1,8 @@
+//! Tools for instantiating a Scenario: the intermediate structures that turn a demand model into
+//! concrete trips, plus importers that build that demand from external data.
+
+mod gtfs;
+mod spawner;
+
+pub use self::gtfs::{GtfsFeed, GtfsId, GtfsIds, GtfsKind, Rider, Route, Stop, Trip};
+pub use self::spawner::{SpawnViolation, TripSpawner, TripSpec};
@@ -1,6 +1,8 @@
 //! Intermediate structures used to instantiate a Scenario. Badly needs simplification:
 //! https://github.com/dabreegster/abstreet/issues/258
 
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
 use abstutil::Timer;
@@ -8,8 +10,8 @@ use geom::Time;
 use map_model::{BuildingID, BusRouteID, BusStopID, Map, PathConstraints, PathRequest, Position};
 
 use crate::{
-    CarID, Command, DrivingGoal, PersonID, Scheduler, SidewalkSpot, TripEndpoint, TripLeg,
-    TripManager, TripMode, TripPurpose, VehicleType,
+    CarID, Command, DrivingGoal, PersonID, Scheduler, SidewalkPOI, SidewalkSpot, TripEndpoint,
+    TripLeg, TripManager, TripMode, TripPurpose, VehicleType,
 };
 
 // TODO Some of these fields are unused now that we separately pass TripEndpoint
@@ -50,17 +52,47 @@ pub enum TripSpec {
         stop1: BusStopID,
         maybe_stop2: Option<BusStopID>,
     },
+    /// Ride a shared vehicle rather than a personally-owned one. True on-demand dispatch (assigning
+    /// the nearest idle vehicle at `start_time` and modeling pickup delay) needs a dedicated
+    /// `TripMode`/`TripLeg` and a dispatcher in the sim runtime, which don't exist yet; until then
+    /// this is scoped to reuse the normal driving legs with the shared vehicle chosen up front.
+    UsingRideHail {
+        /// The shared vehicle assigned to this rider.
+        car: CarID,
+        /// Where the rider waits to be picked up.
+        start: SidewalkSpot,
+        goal: DrivingGoal,
+    },
+}
+
+/// One queued trip, produced by [`TripSpawner::schedule_trip`] and consumed by
+/// [`TripSpawner::finalize`].
+pub struct TripSpawnPlan {
+    person: PersonID,
+    start_time: Time,
+    spec: TripSpec,
+    trip_start: TripEndpoint,
+    purpose: TripPurpose,
+    cancelled: bool,
+    modified: bool,
+    /// Soft target arrival; missing it only records lateness for analytics.
+    preferred_arrival: Option<Time>,
+    /// Hard deadline. Enforced only at spawn time: a trip that can't arrive by this even at
+    /// free-flow speed is cancelled in `finalize`. Bounding the runtime `retry_if_no_room` loop by
+    /// this deadline would require plumbing it through `Command::StartTrip` and the sim step, which
+    /// aren't part of this change, so a trip whose deadline passes mid-retry is not yet cancelled.
+    latest_arrival: Option<Time>,
 }
 
-type TripSpawnPlan = (
-    PersonID,
-    Time,
-    TripSpec,
-    TripEndpoint,
-    TripPurpose,
-    bool,
-    bool,
-);
+/// A way that the queued trips for one person don't make sense, found by
+/// [`TripSpawner::check_feasibility`]. Collected instead of panicking mid-construction, so every
+/// problem in a scenario can be reported at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpawnViolation {
+    pub person: PersonID,
+    pub spec: TripSpec,
+    pub reason: String,
+}
 
 /// This structure is created temporarily by a Scenario or to interactively spawn agents.
 pub struct TripSpawner {
@@ -83,6 +115,8 @@ impl TripSpawner {
         purpose: TripPurpose,
         cancelled: bool,
         modified: bool,
+        preferred_arrival: Option<Time>,
+        latest_arrival: Option<Time>,
         map: &Map,
     ) -> TripSpawnPlan {
         // TODO We'll want to repeat this validation when we spawn stuff later for a second leg...
@@ -93,29 +127,30 @@ impl TripSpawner {
                 use_vehicle,
                 ..
             } => {
-                if start_pos.dist_along() >= map.get_l(start_pos.lane()).length() {
-                    panic!("Can't spawn at {}; it isn't that long", start_pos);
-                }
-                if let DrivingGoal::Border(_, end_lane) = goal {
-                    if start_pos.lane() == *end_lane
-                        && start_pos.dist_along() == map.get_l(*end_lane).length()
-                    {
-                        panic!(
-                            "Can't start at {}; it's the edge of a border already",
-                            start_pos
-                        );
-                    }
-                }
-
                 let constraints = if use_vehicle.1 == VehicleType::Bike {
                     PathConstraints::Bike
                 } else {
                     PathConstraints::Car
                 };
-                if goal.goal_pos(constraints, map).is_none() {
+                let error = if start_pos.dist_along() >= map.get_l(start_pos.lane()).length() {
+                    Some(format!("Can't spawn at {}; it isn't that long", start_pos))
+                } else if matches!(goal, DrivingGoal::Border(_, end_lane)
+                    if start_pos.lane() == *end_lane
+                        && start_pos.dist_along() == map.get_l(*end_lane).length())
+                {
+                    Some(format!(
+                        "Can't start at {}; it's the edge of a border already",
+                        start_pos
+                    ))
+                } else if goal.goal_pos(constraints, map).is_none() {
+                    Some(format!("goal_pos to {:?} for a {:?} failed", goal, constraints))
+                } else {
+                    None
+                };
+                if let Some(error) = error {
                     spec = TripSpec::SpawningFailure {
                         use_vehicle: Some(use_vehicle.clone()),
-                        error: format!("goal_pos to {:?} for a {:?} failed", goal, constraints),
+                        error,
                     };
                 }
             }
@@ -123,10 +158,13 @@ impl TripSpawner {
             TripSpec::UsingParkedCar { .. } => {}
             TripSpec::JustWalking { start, goal, .. } => {
                 if start == goal {
-                    panic!(
-                        "A trip just walking from {:?} to {:?} doesn't make sense",
-                        start, goal
-                    );
+                    spec = TripSpec::SpawningFailure {
+                        use_vehicle: None,
+                        error: format!(
+                            "A trip just walking from {:?} to {:?} doesn't make sense",
+                            start, goal
+                        ),
+                    };
                 }
             }
             TripSpec::UsingBike { start, goal, bike } => {
@@ -177,17 +215,168 @@ impl TripSpawner {
                 }
             }
             TripSpec::UsingTransit { .. } => {}
+            TripSpec::UsingRideHail { .. } => {}
         };
 
-        (
-            person, start_time, spec, trip_start, purpose, cancelled, modified,
-        )
+        TripSpawnPlan {
+            person,
+            start_time,
+            spec,
+            trip_start,
+            purpose,
+            cancelled,
+            modified,
+            preferred_arrival,
+            latest_arrival,
+        }
     }
 
     pub fn schedule_trips(&mut self, trips: Vec<TripSpawnPlan>) {
         self.trips.extend(trips);
     }
 
+    /// Replays each person's scheduled trips in order and returns every constraint violation,
+    /// rather than discovering them one panic at a time during `finalize`. Call after
+    /// `schedule_trips` but before `finalize`.
+    pub fn check_feasibility(&self, map: &Map) -> Vec<SpawnViolation> {
+        let mut violations = Vec::new();
+
+        // Group each person's plans so we can walk them in chronological order. Along the way,
+        // remember the first person to claim each vehicle so we can flag anyone else picking it up
+        // as a parked car.
+        let mut by_person: BTreeMap<PersonID, Vec<&TripSpawnPlan>> = BTreeMap::new();
+        let mut car_owner: BTreeMap<CarID, PersonID> = BTreeMap::new();
+        for plan in &self.trips {
+            by_person
+                .entry(plan.person)
+                .or_insert_with(Vec::new)
+                .push(plan);
+            if let Some(car) = plan.spec.use_vehicle() {
+                car_owner.entry(car).or_insert(plan.person);
+            }
+        }
+
+        for (person, mut plans) in by_person {
+            plans.sort_by_key(|plan| plan.start_time);
+
+            // Where the person physically is after the previous leg, and where each of their
+            // vehicles was last left parked.
+            let mut prev_goal: Option<TripEndpoint> = None;
+            let mut parked_at: BTreeMap<CarID, BuildingID> = BTreeMap::new();
+            // The half-open time window [start, free-flow arrival) each vehicle is claimed for.
+            let mut claimed: BTreeMap<CarID, (Time, Time, TripSpec)> = BTreeMap::new();
+
+            for plan in &plans {
+                let start_time = plan.start_time;
+                let spec = &plan.spec;
+                let trip_start = &plan.trip_start;
+                // End the claim when the trip would finish at free-flow speed, so two trips that
+                // genuinely overlap in time can be caught reusing the same vehicle. Falling back to
+                // start_time keeps the window non-overlapping when there's no path to estimate.
+                let end_time = spec
+                    .predict_free_flow_arrival(start_time, map)
+                    .unwrap_or(start_time);
+
+                // (1) Consecutive trips must chain spatially.
+                if let Some(prev_goal) = &prev_goal {
+                    if prev_goal != trip_start {
+                        violations.push(SpawnViolation {
+                            person,
+                            spec: spec.clone(),
+                            reason: format!(
+                                "trip starts at {:?}, but the previous trip ended at {:?}",
+                                trip_start, prev_goal
+                            ),
+                        });
+                    }
+                }
+
+                // (2) No two overlapping trips of this person may claim the same vehicle. Overlaps
+                // between different people aren't checked here.
+                if let Some(car) = spec.use_vehicle() {
+                    if let Some((since, until, other)) = claimed.get(&car) {
+                        if start_time < *until && *since < end_time {
+                            violations.push(SpawnViolation {
+                                person,
+                                spec: spec.clone(),
+                                reason: format!(
+                                    "{} is already in use from {} to {} by {:?}",
+                                    car, since, until, other
+                                ),
+                            });
+                        }
+                    }
+                    claimed.insert(car, (start_time, end_time, spec.clone()));
+                }
+
+                // (3) A parked car must belong to this person and be picked up where it was left.
+                if let TripSpec::UsingParkedCar {
+                    car, start_bldg, ..
+                } = spec
+                {
+                    if let Some(owner) = car_owner.get(car) {
+                        if *owner != person {
+                            violations.push(SpawnViolation {
+                                person,
+                                spec: spec.clone(),
+                                reason: format!("{} is owned by {}, not this person", car, owner),
+                            });
+                        }
+                    }
+                    if let Some(last) = parked_at.get(car) {
+                        if last != start_bldg {
+                            violations.push(SpawnViolation {
+                                person,
+                                spec: spec.clone(),
+                                reason: format!(
+                                    "{} was last parked at {}, not {}",
+                                    car, last, start_bldg
+                                ),
+                            });
+                        }
+                    }
+                }
+
+                // (4) Transit stops must lie on the named route.
+                if let TripSpec::UsingTransit {
+                    route,
+                    stop1,
+                    maybe_stop2,
+                    ..
+                } = spec
+                {
+                    let stops = &map.get_br(*route).stops;
+                    if !stops.contains(stop1) {
+                        violations.push(SpawnViolation {
+                            person,
+                            spec: spec.clone(),
+                            reason: format!("{} isn't served by {}", stop1, route),
+                        });
+                    }
+                    if let Some(stop2) = maybe_stop2 {
+                        if !stops.contains(stop2) {
+                            violations.push(SpawnViolation {
+                                person,
+                                spec: spec.clone(),
+                                reason: format!("{} isn't served by {}", stop2, route),
+                            });
+                        }
+                    }
+                }
+
+                // Remember where this leg leaves the person and any parked vehicle.
+                if let (Some(car), Some(DrivingGoal::ParkNear(b))) =
+                    (spec.use_vehicle(), spec.driving_goal())
+                {
+                    parked_at.insert(car, b);
+                }
+                prev_goal = spec.goal_endpoint();
+            }
+        }
+
+        violations
+    }
+
     pub fn finalize(
         mut self,
         map: &Map,
@@ -196,10 +385,47 @@ impl TripSpawner {
         timer: &mut Timer,
     ) {
         timer.start_iter("spawn trips", self.trips.len());
-        for (p, start_time, spec, trip_start, purpose, cancelled, modified) in self.trips.drain(..)
-        {
+        for plan in self.trips.drain(..) {
+            let TripSpawnPlan {
+                person: p,
+                start_time,
+                mut spec,
+                trip_start,
+                purpose,
+                cancelled,
+                modified,
+                preferred_arrival,
+                latest_arrival,
+            } = plan;
             timer.next();
 
+            // Predict a free-flow arrival from the first leg's path and enforce the time windows at
+            // spawn time. A hard deadline that's already unreachable cancels the trip; a missed soft
+            // target is only recorded for analytics. This does not bound the runtime retry loop --
+            // see the latest_arrival field doc.
+            if let Some(predicted) = spec.predict_free_flow_arrival(start_time, map) {
+                if let Some(latest) = latest_arrival {
+                    if predicted > latest {
+                        spec = TripSpec::SpawningFailure {
+                            use_vehicle: spec.use_vehicle(),
+                            error: format!(
+                                "can't arrive by {}; free-flow arrival alone is {}",
+                                latest, predicted
+                            ),
+                        };
+                    }
+                }
+                if let Some(preferred) = preferred_arrival {
+                    if predicted > preferred {
+                        info!(
+                            "Trip for {} will arrive {} late (free-flow estimate)",
+                            p,
+                            predicted - preferred
+                        );
+                    }
+                }
+            }
+
             // TODO clone() is super weird to do here, but we just need to make the borrow checker
             // happy. All we're doing is grabbing IDs off this.
             let person = trips.get_person(p).unwrap().clone();
@@ -228,27 +454,33 @@ impl TripSpawner {
                         map,
                     )
                 }
-                TripSpec::SpawningFailure { use_vehicle, .. } => {
-                    // TODO Need to plumb TripInfo into here
-                    todo!()
-                    /*let mut legs = vec![TripLeg::Drive(use_vehicle, goal.clone())];
-                    if let DrivingGoal::ParkNear(b) = goal {
-                        legs.push(TripLeg::Walk(SidewalkSpot::building(b, map)));
-                    }
-                    trips.new_trip(
+                TripSpec::SpawningFailure { use_vehicle, error } => {
+                    // We don't know the real mode of the trip that failed to spawn, but guess from
+                    // the vehicle that would've been used so analytics still bucket it sensibly.
+                    let mode = match use_vehicle {
+                        Some(use_vehicle) => {
+                            if use_vehicle.1 == VehicleType::Bike {
+                                TripMode::Bike
+                            } else {
+                                TripMode::Drive
+                            }
+                        }
+                        None => TripMode::Walk,
+                    };
+                    // new_trip requires at least one leg, so hand it a single placeholder walk.
+                    // The trip is cancelled immediately, so the leg is never executed.
+                    let trip = trips.new_trip(
                         person.id,
                         start_time,
                         trip_start,
-                        if use_vehicle.1 == VehicleType::Bike {
-                            TripMode::Bike
-                        } else {
-                            TripMode::Drive
-                        },
+                        mode,
                         purpose,
                         modified,
-                        legs,
+                        vec![TripLeg::Walk(SidewalkSpot::deferred_parking_spot())],
                         map,
-                    )*/
+                    );
+                    trips.cancel_unstarted_trip(trip, error);
+                    continue;
                 }
                 TripSpec::UsingParkedCar { car, goal, .. } => {
                     let mut legs = vec![
@@ -336,6 +568,23 @@ impl TripSpawner {
                         map,
                     )
                 }
+                TripSpec::UsingRideHail { car, start, goal } => {
+                    // Walk to the pickup spot, then ride the shared vehicle to the goal.
+                    let mut legs = vec![TripLeg::Walk(start), TripLeg::Drive(car, goal.clone())];
+                    if let DrivingGoal::ParkNear(b) = goal {
+                        legs.push(TripLeg::Walk(SidewalkSpot::building(b, map)));
+                    }
+                    trips.new_trip(
+                        person.id,
+                        start_time,
+                        trip_start,
+                        TripMode::Drive,
+                        purpose,
+                        modified,
+                        legs,
+                        map,
+                    )
+                }
             };
 
             if cancelled {
@@ -351,6 +600,53 @@ impl TripSpawner {
 }
 
 impl TripSpec {
+    /// The vehicle this trip claims up front, if any.
+    fn use_vehicle(&self) -> Option<CarID> {
+        match self {
+            TripSpec::VehicleAppearing { use_vehicle, .. } => Some(*use_vehicle),
+            TripSpec::UsingParkedCar { car, .. } => Some(*car),
+            TripSpec::UsingBike { bike, .. } => Some(*bike),
+            TripSpec::UsingRideHail { car, .. } => Some(*car),
+            TripSpec::SpawningFailure { use_vehicle, .. } => *use_vehicle,
+            TripSpec::JustWalking { .. } | TripSpec::UsingTransit { .. } => None,
+        }
+    }
+
+    /// Where a driven or biked trip ends up, if this trip drives at all.
+    fn driving_goal(&self) -> Option<DrivingGoal> {
+        match self {
+            TripSpec::VehicleAppearing { goal, .. }
+            | TripSpec::UsingParkedCar { goal, .. }
+            | TripSpec::UsingBike { goal, .. }
+            | TripSpec::UsingRideHail { goal, .. } => Some(goal.clone()),
+            TripSpec::SpawningFailure { .. }
+            | TripSpec::JustWalking { .. }
+            | TripSpec::UsingTransit { .. } => None,
+        }
+    }
+
+    /// Where this trip leaves the person, for checking that one leg chains into the next.
+    fn goal_endpoint(&self) -> Option<TripEndpoint> {
+        match self {
+            TripSpec::VehicleAppearing { goal, .. }
+            | TripSpec::UsingParkedCar { goal, .. }
+            | TripSpec::UsingBike { goal, .. }
+            | TripSpec::UsingRideHail { goal, .. } => Some(endpoint_for_goal(goal)),
+            TripSpec::JustWalking { goal, .. } | TripSpec::UsingTransit { goal, .. } => {
+                endpoint_for_spot(goal)
+            }
+            TripSpec::SpawningFailure { .. } => None,
+        }
+    }
+
+    /// A rough free-flow arrival time, based on the first leg's path. Returns `None` when there's
+    /// no path to estimate from (e.g. a deferred parking lookup).
+    fn predict_free_flow_arrival(&self, start_time: Time, map: &Map) -> Option<Time> {
+        let req = self.get_pathfinding_request(map)?;
+        let path = map.pathfind(req)?;
+        Some(start_time + path.estimate_duration(map))
+    }
+
     pub(crate) fn get_pathfinding_request(&self, map: &Map) -> Option<PathRequest> {
         match self {
             TripSpec::VehicleAppearing {
@@ -388,6 +684,28 @@ impl TripSpec {
                 end: SidewalkSpot::bus_stop(*stop1, map).sidewalk_pos,
                 constraints: PathConstraints::Pedestrian,
             }),
+            // `start` is already the pickup spot, so this is an empty walk from `start` to itself.
+            // The shared vehicle is the one named in the spec; its route isn't requested here.
+            TripSpec::UsingRideHail { start, .. } => Some(PathRequest {
+                start: start.sidewalk_pos,
+                end: start.sidewalk_pos,
+                constraints: PathConstraints::Pedestrian,
+            }),
         }
     }
 }
+
+fn endpoint_for_goal(goal: &DrivingGoal) -> TripEndpoint {
+    match goal {
+        DrivingGoal::ParkNear(b) => TripEndpoint::Bldg(*b),
+        DrivingGoal::Border(i, _) => TripEndpoint::Border(*i),
+    }
+}
+
+fn endpoint_for_spot(spot: &SidewalkSpot) -> Option<TripEndpoint> {
+    match spot.connection {
+        SidewalkPOI::Building(b) => Some(TripEndpoint::Bldg(b)),
+        SidewalkPOI::Border(i, ..) => Some(TripEndpoint::Border(i)),
+        _ => None,
+    }
+}